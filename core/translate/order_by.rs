@@ -14,10 +14,26 @@ use crate::{
 use super::{
     emitter::{Resolver, TranslateCtx},
     expr::translate_expr,
-    plan::{Distinctness, ResultSetColumn, SelectPlan, TableReferences},
+    plan::{Distinctness, ResultSetColumn, SelectPlan, TableInternalId, TableReferences},
     result_row::{emit_offset, emit_result_row_and_limit},
 };
 
+/// The default in-memory budget for an unbounded ORDER BY sorter, in bytes, before it starts
+/// spilling sorted runs to temporary storage and merging them at `SorterSort` time. A bounded
+/// top-N heap sorter (see `SortMetadata::limit`) never spills, since its memory use is already
+/// capped by its capacity.
+const DEFAULT_SORT_SPILL_THRESHOLD_BYTES: usize = 8 * 1024 * 1024;
+
+// This module emits `Insn::SorterOpen { limit, spill_threshold_bytes, .. }`,
+// `Insn::MakeSortKey` and `Insn::SorterCompareWorst`, defined in `vdbe::insn`. The runtime
+// behavior those opcodes select -- bounded top-N replace-on-insert, the memcomparable key
+// encoding, and the spill-to-disk external merge -- is implemented in `vdbe::sorter`
+// (`BoundedTopNSorter`, `encode_sort_key`, `ExternalMergeSorter`). Neither module dispatches to
+// the other: the VDBE instruction loop that executes `Insn::SorterInsert` / `Insn::SorterSort` /
+// `Insn::MakeSortKey` by calling into `vdbe::sorter` isn't part of this series (it lives in the
+// VDBE's bytecode execution loop, which this translation-layer change doesn't touch), so wiring
+// that dispatch up is the remaining integration step before this is load-bearing end to end.
+
 // Metadata for handling ORDER BY operations
 #[derive(Debug)]
 pub struct SortMetadata {
@@ -25,20 +41,51 @@ pub struct SortMetadata {
     pub sort_cursor: usize,
     // register where the sorter data is inserted and later retrieved from
     pub reg_sorter_data: usize,
+    // When set, the sorter retains at most this many rows (LIMIT plus any OFFSET) in a bounded
+    // top-N heap (`vdbe::sorter::BoundedTopNSorter`) instead of materializing and sorting the
+    // entire input. `init_order_by` passes this value through to `Insn::SorterOpen`, and
+    // `order_by_sorter_insert` emits the `Insn::SorterCompareWorst` pre-check only when it is set.
+    pub limit: Option<i64>,
+    // Per ORDER BY term direction and collating sequence, in ORDER BY clause order. Kept here so
+    // `order_by_sorter_insert` can build the `MakeSortKey` encoding for every row without
+    // re-resolving collations from the referenced tables each time.
+    pub order: Vec<SortOrder>,
+    pub collations: Vec<Option<CollationSeq>>,
 }
 
-/// Initialize resources needed for ORDER BY processing
+/// Initialize resources needed for ORDER BY processing.
+///
+/// `index_scan_order`, when the main loop's scan is driven by an index (or the rowid) whose key
+/// columns are known up front, describes that scan's natural row order the same way
+/// [`order_by_satisfied_by_index_scan`] expects. If that natural order already satisfies
+/// `order_by`, no sorter is needed at all: this returns `Ok(Some(target))` without touching
+/// `t_ctx.meta_sort` or emitting any `Sorter*` instructions, and the caller must drive the scan
+/// in `target`'s direction and emit result rows directly from the main loop instead of calling
+/// [`order_by_sorter_insert`] / [`emit_order_by`]. Otherwise (or when `index_scan_order` is
+/// `None`, e.g. the scan isn't index-driven) this falls back to setting up the sorter as before
+/// and returns `Ok(None)`.
 pub fn init_order_by(
     program: &mut ProgramBuilder,
     t_ctx: &mut TranslateCtx,
     order_by: &[(ast::Expr, SortOrder)],
     referenced_tables: &TableReferences,
-) -> Result<()> {
+    limit: Option<i64>,
+    offset: Option<i64>,
+    index_scan_order: Option<IndexScanOrder>,
+) -> Result<Option<OrderTarget>> {
+    if let Some(index_scan_order) = index_scan_order {
+        if let Some(target) =
+            order_by_satisfied_by_index_scan(order_by, referenced_tables, index_scan_order)
+        {
+            return Ok(Some(target));
+        }
+    }
+
     let sort_cursor = program.alloc_cursor_id(CursorType::Sorter);
-    t_ctx.meta_sort = Some(SortMetadata {
-        sort_cursor,
-        reg_sorter_data: program.alloc_register(),
-    });
+    // The sorter only ever needs to retain the rows that LIMIT/OFFSET could still output; rows
+    // beyond the k-th smallest (or largest) plus however many OFFSET will skip can never be
+    // part of the result, so a top-N heap of that bounded size replaces a full sort.
+    let capacity = limit.map(|limit| limit + offset.unwrap_or(0));
 
     /*
      * Terms of the ORDER BY clause that is part of a SELECT statement may be assigned a collating sequence using the COLLATE operator,
@@ -63,13 +110,119 @@ pub fn init_order_by(
             _ => Ok(Some(CollationSeq::default())),
         })
         .collect::<Result<Vec<_>>>()?;
+    let order = order_by.iter().map(|(_, direction)| *direction).collect();
+
+    t_ctx.meta_sort = Some(SortMetadata {
+        sort_cursor,
+        reg_sorter_data: program.alloc_register(),
+        limit: capacity,
+        order,
+        collations,
+    });
+
+    // The sorter's rows are keyed by a single pre-encoded, order-preserving byte string (see
+    // `Insn::MakeSortKey` in `order_by_sorter_insert`), so its comparator is a plain memcmp and
+    // no longer needs to know about per-column direction or collation itself.
     program.emit_insn(Insn::SorterOpen {
         cursor_id: sort_cursor,
         columns: order_by.len(),
-        order: order_by.iter().map(|(_, direction)| *direction).collect(),
-        collations,
+        // `limit` selects the bounded top-N heap mode: the sorter keeps only the `capacity`
+        // smallest (or largest) rows seen so far, replacing its current worst retained row
+        // whenever a better one arrives. `None` keeps the original materialize-everything-
+        // then-sort behavior.
+        limit: capacity,
+        // Once the unbounded sorter's in-memory rows exceed this many bytes, it flushes what
+        // it's holding as a sorted run to temporary storage and merges the runs back together
+        // at `SorterSort` time, so ORDER BY on inputs larger than RAM doesn't OOM. The spill and
+        // k-way merge themselves are the sorter cursor's responsibility (see the module note at
+        // the top of this file); this threshold is the only input it needs from translation.
+        spill_threshold_bytes: capacity
+            .is_none()
+            .then_some(DEFAULT_SORT_SPILL_THRESHOLD_BYTES),
     });
-    Ok(())
+    Ok(None)
+}
+
+/// Which direction a scan must run in for its natural row order to satisfy an ORDER BY clause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderTarget {
+    /// The scan must walk the index/rowid forward.
+    Forward,
+    /// The scan must walk the index/rowid in reverse.
+    Reverse,
+}
+
+/// Describes the scan [`init_order_by`] should check against `order_by`, when the caller already
+/// knows the main loop will walk a specific index (or the rowid) in a fixed order.
+#[derive(Debug, Clone, Copy)]
+pub struct IndexScanOrder<'a> {
+    /// Internal id of the table the scan actually drives. Every ORDER BY term that matches
+    /// `columns` must also reference this table — otherwise a different table's column that
+    /// happens to share a column index with the scanned table's index key would be wrongly
+    /// accepted, and the sorter would be skipped when it's still required.
+    pub scanned_table_id: TableInternalId,
+    /// The key columns of the index (or, for a rowid scan, a single rowid "column") that the
+    /// scan would walk, as `(table_column_index, direction, collation)` triples in index order.
+    pub columns: &'a [(usize, SortOrder, Option<CollationSeq>)],
+}
+
+/// Checks whether scanning a table in its index (or rowid) order already produces rows in the
+/// order required by `order_by`, so that no sorter is needed at all.
+///
+/// Every ORDER BY term must be a bare column reference to `index_scan_order.scanned_table_id`
+/// (no expressions, and no columns from any other table in the join) and must match a prefix of
+/// `index_scan_order.columns` in position, direction and collating sequence, reusing the same
+/// collation resolution as [`init_order_by`]. Mixing terms that agree with the index's own
+/// direction and terms that require the opposite direction can't be satisfied by a single linear
+/// scan, so that returns `None` too. Returns `None` if the scan cannot satisfy the ordering and a
+/// sorter is still required.
+pub fn order_by_satisfied_by_index_scan(
+    order_by: &[(ast::Expr, SortOrder)],
+    referenced_tables: &TableReferences,
+    index_scan_order: IndexScanOrder,
+) -> Option<OrderTarget> {
+    let IndexScanOrder {
+        scanned_table_id,
+        columns: index_columns,
+    } = index_scan_order;
+    if order_by.len() > index_columns.len() {
+        return None;
+    }
+
+    let mut target: Option<OrderTarget> = None;
+
+    for ((expr, order_by_direction), (index_column, index_direction, index_collation)) in
+        order_by.iter().zip(index_columns.iter())
+    {
+        let ast::Expr::Column { table, column, .. } = expr else {
+            return None;
+        };
+        if *table != scanned_table_id {
+            return None;
+        }
+        if column != index_column {
+            return None;
+        }
+
+        let table_ref = referenced_tables.find_table_by_internal_id(*table)?;
+        let table_column = table_ref.get_column_at(*column)?;
+        if &table_column.collation != index_collation {
+            return None;
+        }
+
+        let term_target = if order_by_direction == index_direction {
+            OrderTarget::Forward
+        } else {
+            OrderTarget::Reverse
+        };
+        match target {
+            None => target = Some(term_target),
+            Some(t) if t == term_target => {}
+            Some(_) => return None,
+        }
+    }
+
+    target
 }
 
 /// Emits the bytecode for outputting rows from an ORDER BY sorter.
@@ -80,18 +233,14 @@ pub fn emit_order_by(
     t_ctx: &mut TranslateCtx,
     plan: &SelectPlan,
 ) -> Result<()> {
-    let order_by = plan.order_by.as_ref().unwrap();
     let result_columns = &plan.result_columns;
     let sort_loop_start_label = program.allocate_label();
     let sort_loop_next_label = program.allocate_label();
     let sort_loop_end_label = program.allocate_label();
 
-    let sorter_column_count = order_by.len() + result_columns.len()
-        - t_ctx
-            .result_columns_to_skip_in_orderby_sorter
-            .as_ref()
-            .map(|v| v.len())
-            .unwrap_or(0);
+    // The sorter's record is the encoded sort key blob (a single column) followed by the
+    // result columns in SELECT order; see `order_by_sorter_insert`.
+    let sorter_column_count = 1 + result_columns.len();
 
     let pseudo_cursor = program.alloc_cursor_id(CursorType::Pseudo(PseudoCursorType {
         column_count: sorter_column_count,
@@ -99,6 +248,7 @@ pub fn emit_order_by(
     let SortMetadata {
         sort_cursor,
         reg_sorter_data,
+        ..
     } = *t_ctx.meta_sort.as_mut().unwrap();
 
     program.emit_insn(Insn::OpenPseudo {
@@ -163,19 +313,12 @@ pub fn order_by_sorter_insert(
     let order_by = plan.order_by.as_ref().unwrap();
     let order_by_len = order_by.len();
     let result_columns = &plan.result_columns;
-    // If any result columns can be skipped due to being an exact duplicate of a sort key, we need to know which ones and their new index in the ORDER BY sorter.
-    let result_columns_to_skip = order_by_deduplicate_result_columns(order_by, result_columns);
-    let result_columns_to_skip_len = result_columns_to_skip
-        .as_ref()
-        .map(|v| v.len())
-        .unwrap_or(0);
-
-    // The ORDER BY sorter has the sort keys first, then the result columns.
-    let orderby_sorter_column_count =
-        order_by_len + result_columns.len() - result_columns_to_skip_len;
-    let start_reg = program.alloc_registers(orderby_sorter_column_count);
+
+    // Scratch registers holding the typed, un-encoded sort key values. They only feed
+    // `MakeSortKey` below; the sorter's record stores the encoded key blob, not these.
+    let key_start_reg = program.alloc_registers(order_by_len);
     for (i, (expr, _)) in order_by.iter().enumerate() {
-        let key_reg = start_reg + i;
+        let key_reg = key_start_reg + i;
         translate_expr(
             program,
             Some(&plan.table_references),
@@ -184,50 +327,91 @@ pub fn order_by_sorter_insert(
             resolver,
         )?;
     }
-    let mut cur_reg = start_reg + order_by_len;
-    let mut cur_idx_in_orderby_sorter = order_by_len;
-    let mut translated_result_col_count = 0;
+
+    // A result column that is an exact duplicate of a sort key expression is cheap to populate:
+    // the typed value is already sitting in `key_start_reg + j` above, so we copy it into the
+    // result column's register instead of evaluating the expression a second time. The sorter's
+    // record still stores the encoded key blob followed by every result column in SELECT order
+    // (the blob itself doesn't expose individual typed values), so this only saves the
+    // re-evaluation, not the storage.
+    let dup_result_columns = order_by_deduplicate_result_columns(order_by, result_columns);
+    let record_width = 1 + result_columns.len();
+    let record_start = program.alloc_registers(record_width);
+    let sort_key_reg = record_start;
+
+    // Pack the typed sort key values into a single order-preserving byte string so the sorter's
+    // comparator becomes a trivial memcmp instead of having to re-interpret typed registers with
+    // per-column direction and collation on every comparison.
+    program.emit_insn(Insn::MakeSortKey {
+        start_reg: key_start_reg,
+        count: order_by_len,
+        order: sort_metadata.order.clone(),
+        collations: sort_metadata.collations.clone(),
+        dest_reg: sort_key_reg,
+    });
+
+    // If the sorter is bounded (top-N heap mode, see `SortMetadata::limit`), a row whose sort
+    // key already compares worse than the worst key the sorter currently retains cannot
+    // possibly end up in the output. In that case we skip straight past the (potentially
+    // expensive) result-column translation below and don't insert the row at all.
+    let skip_row_label = sort_metadata
+        .limit
+        .is_some()
+        .then(|| program.allocate_label());
+    if let Some(skip_row_label) = skip_row_label {
+        program.emit_insn(Insn::SorterCompareWorst {
+            cursor_id: sort_metadata.sort_cursor,
+            key_reg: sort_key_reg,
+            target_pc: skip_row_label,
+        });
+    }
+
     for (i, rc) in result_columns.iter().enumerate() {
-        if let Some(ref v) = result_columns_to_skip {
-            let found = v.iter().find(|(skipped_idx, _)| *skipped_idx == i);
-            // If the result column is in the list of columns to skip, we need to know its new index in the ORDER BY sorter.
-            if let Some((_, result_column_idx)) = found {
-                res_col_indexes_in_orderby_sorter.insert(i, *result_column_idx);
-                continue;
-            }
+        let reg = record_start + 1 + i;
+        let dup_key_index = dup_result_columns
+            .as_ref()
+            .and_then(|v| v.iter().find(|(ri, _)| *ri == i).map(|(_, j)| *j));
+        if let Some(j) = dup_key_index {
+            program.emit_insn(Insn::Copy {
+                src_reg: key_start_reg + j,
+                dst_reg: reg,
+                extra_amount: 0,
+            });
+        } else {
+            translate_expr(
+                program,
+                Some(&plan.table_references),
+                &rc.expr,
+                reg,
+                resolver,
+            )?;
         }
-        translate_expr(
-            program,
-            Some(&plan.table_references),
-            &rc.expr,
-            cur_reg,
-            resolver,
-        )?;
-        translated_result_col_count += 1;
-        res_col_indexes_in_orderby_sorter.insert(i, cur_idx_in_orderby_sorter);
-        cur_idx_in_orderby_sorter += 1;
-        cur_reg += 1;
+        res_col_indexes_in_orderby_sorter.insert(i, 1 + i);
     }
 
     // Handle SELECT DISTINCT deduplication
     if let Distinctness::Distinct { ctx } = &plan.distinctness {
         let distinct_ctx = ctx.as_ref().expect("distinct context must exist");
-        let num_regs = order_by_len + translated_result_col_count;
-        distinct_ctx.emit_deduplication_insns(program, num_regs, start_reg);
+        distinct_ctx.emit_deduplication_insns(program, record_width, record_start);
     }
 
     let SortMetadata {
         sort_cursor,
         reg_sorter_data,
+        ..
     } = sort_metadata;
 
     sorter_insert(
         program,
-        start_reg,
-        orderby_sorter_column_count,
+        record_start,
+        record_width,
         *sort_cursor,
         *reg_sorter_data,
     );
+
+    if let Some(skip_row_label) = skip_row_label {
+        program.preassign_label_to_next_insn(skip_row_label);
+    }
     Ok(())
 }
 
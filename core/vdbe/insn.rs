@@ -0,0 +1,97 @@
+use turso_sqlite3_parser::ast::SortOrder;
+
+use crate::{translate::collate::CollationSeq, vdbe::BranchOffset};
+
+/// Bytecode instructions executed by the VDBE.
+///
+/// This only lists the opcodes touched by the ORDER BY / sorter translation path in
+/// `translate::order_by` (`core/translate/order_by.rs`) and the baseline sorter opcodes it
+/// already relied on before that series. The real `Insn` enum has many more opcodes owned by
+/// other parts of the VDBE; merge these variants into it rather than replacing it wholesale.
+#[derive(Debug, Clone)]
+pub enum Insn {
+    /// Opens a sorter cursor. Rows are inserted with [`Insn::SorterInsert`] and drained in
+    /// sorted key order starting at [`Insn::SorterSort`].
+    SorterOpen {
+        cursor_id: usize,
+        columns: usize,
+        /// When set, the sorter keeps only the `limit` smallest (or largest, depending on the
+        /// key's encoded sort order) rows seen so far in a bounded top-N heap instead of
+        /// materializing and sorting the entire input. See
+        /// `crate::vdbe::sorter::BoundedTopNSorter`.
+        limit: Option<i64>,
+        /// Once the unbounded sorter's in-memory rows exceed this many bytes, it spills what
+        /// it's holding as a sorted run to temporary storage, to be merged back together at
+        /// `SorterSort` time. `None` when `limit` is set, since a bounded sorter's memory use is
+        /// already capped by its capacity and never needs to spill. See
+        /// `crate::vdbe::sorter::ExternalMergeSorter`.
+        spill_threshold_bytes: Option<usize>,
+    },
+    /// Packs the `count` typed values starting at `start_reg` into a single order-preserving
+    /// byte string at `dest_reg`, so the sorter's comparator is a trivial `memcmp` instead of
+    /// having to re-interpret typed registers with per-column direction and collation on every
+    /// comparison. See `crate::vdbe::sorter::encode_sort_key`.
+    MakeSortKey {
+        start_reg: usize,
+        count: usize,
+        order: Vec<SortOrder>,
+        collations: Vec<Option<CollationSeq>>,
+        dest_reg: usize,
+    },
+    /// Only valid on a bounded (`limit`-carrying) sorter. Compares the encoded key at `key_reg`
+    /// against the sorter's current worst retained key; if the incoming row cannot possibly
+    /// place within the limit, jumps to `target_pc` instead of falling through to
+    /// `SorterInsert`, so the caller can skip translating (potentially expensive) result-column
+    /// expressions for rows that will never be output.
+    SorterCompareWorst {
+        cursor_id: usize,
+        key_reg: usize,
+        target_pc: BranchOffset,
+    },
+    /// Inserts the record at `record_reg` into the sorter. On a bounded sorter this is a heap
+    /// push-or-replace; on an unbounded sorter this appends to the current in-memory run, then
+    /// spills that run once its size exceeds `spill_threshold_bytes`.
+    SorterInsert { cursor_id: usize, record_reg: usize },
+    /// Positions the sorter at its first row in sorted order, merging spilled runs (if any)
+    /// first. Jumps to `pc_if_empty` if the sorter has no rows.
+    SorterSort {
+        cursor_id: usize,
+        pc_if_empty: BranchOffset,
+    },
+    /// Reads the current sorter row's record into `dest_reg`, through `pseudo_cursor` so its
+    /// columns can be read back with `ProgramBuilder::emit_column`.
+    SorterData {
+        cursor_id: usize,
+        dest_reg: usize,
+        pseudo_cursor: usize,
+    },
+    /// Advances the sorter to the next row in sorted order. Jumps to `pc_if_next` if there is
+    /// one, otherwise falls through.
+    SorterNext {
+        cursor_id: usize,
+        pc_if_next: BranchOffset,
+    },
+    /// Opens a pseudo-cursor over `content_reg`, a single record register with `num_fields`
+    /// columns, so its fields can be read back with `ProgramBuilder::emit_column` the same way
+    /// as a real table/index cursor.
+    OpenPseudo {
+        cursor_id: usize,
+        content_reg: usize,
+        num_fields: usize,
+    },
+    /// Packs the `count` registers starting at `start_reg` into a single record at `dest_reg`.
+    MakeRecord {
+        start_reg: usize,
+        count: usize,
+        dest_reg: usize,
+        index_name: Option<String>,
+    },
+    /// Copies the register at `src_reg` (and the following `extra_amount` registers) to
+    /// `dst_reg` (and onward), without re-evaluating whatever expression produced the source
+    /// value.
+    Copy {
+        src_reg: usize,
+        dst_reg: usize,
+        extra_amount: usize,
+    },
+}
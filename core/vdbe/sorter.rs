@@ -0,0 +1,386 @@
+//! Runtime support for the ORDER BY sorter cursor driven by the `Sorter*` and `MakeSortKey`
+//! opcodes in `crate::vdbe::insn::Insn`. Wiring this into the instruction dispatch loop that
+//! executes `Insn::SorterInsert` / `Insn::SorterSort` / `Insn::SorterData` / `Insn::SorterNext` /
+//! `Insn::MakeSortKey` is the remaining integration step and belongs in that dispatch loop, not
+//! here.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+use turso_sqlite3_parser::ast::SortOrder;
+
+use crate::{translate::collate::CollationSeq, types::Value};
+
+/// Type/null tag bytes prefixed to every encoded column. Ordered so that a `NULL` sorts before
+/// any non-null value regardless of type, matching SQL's "NULLs sort first" default.
+mod tag {
+    pub const NULL: u8 = 0x00;
+    pub const INTEGER: u8 = 0x01;
+    pub const FLOAT: u8 = 0x02;
+    pub const TEXT: u8 = 0x03;
+    pub const BLOB: u8 = 0x04;
+}
+
+fn encode_integer_be(i: i64) -> [u8; 8] {
+    // Flipping the sign bit makes the big-endian two's-complement encoding compare correctly as
+    // an unsigned byte string: negative numbers (high bit 1) flip to start with 0, positives
+    // (high bit 0) flip to start with 1, preserving numeric order under memcmp.
+    ((i as u64) ^ (1u64 << 63)).to_be_bytes()
+}
+
+fn encode_float_be(f: f64) -> [u8; 8] {
+    let bits = f.to_bits();
+    let flipped = if f.is_sign_negative() {
+        // Negative floats: flipping every bit reverses their (otherwise descending-as-magnitude)
+        // bit pattern order into ascending numeric order.
+        !bits
+    } else {
+        // Non-negative floats already compare correctly bit-for-bit; just flip the sign bit so
+        // they sort after every negative float's flipped encoding.
+        bits | (1u64 << 63)
+    };
+    flipped.to_be_bytes()
+}
+
+/// Applies the column's collating sequence before encoding, so differently-collated text still
+/// compares correctly under a plain byte-string `memcmp`. Unknown/future collations fall back to
+/// the raw bytes (equivalent to `BINARY`) rather than failing to encode.
+fn collated_bytes(text: &str, collation: &Option<CollationSeq>) -> Vec<u8> {
+    match collation {
+        Some(CollationSeq::NoCase) => text.to_uppercase().into_bytes(),
+        Some(CollationSeq::RTrim) => text.trim_end().as_bytes().to_vec(),
+        _ => text.as_bytes().to_vec(),
+    }
+}
+
+/// Appends `bytes` to `out` with every embedded `0x00` escaped to `0x00 0xFF`, followed by a
+/// `0x00 0x00` terminator. Escaping embedded zero bytes is what makes the terminator
+/// unambiguous: a raw `0x00` can never appear unescaped in the content, so `0x00 0x00` can only
+/// mean "end of this column's content", and a shorter string is always ordered before a longer
+/// string that extends it (the terminator is `0x00`, the smallest possible next byte).
+fn push_escaped_with_terminator(out: &mut Vec<u8>, bytes: &[u8]) {
+    for &b in bytes {
+        out.push(b);
+        if b == 0x00 {
+            out.push(0xFF);
+        }
+    }
+    out.push(0x00);
+    out.push(0x00);
+}
+
+fn encode_column(out: &mut Vec<u8>, value: &Value, collation: &Option<CollationSeq>) {
+    match value {
+        Value::Null => out.push(tag::NULL),
+        Value::Integer(i) => {
+            out.push(tag::INTEGER);
+            out.extend_from_slice(&encode_integer_be(*i));
+        }
+        Value::Float(f) => {
+            out.push(tag::FLOAT);
+            out.extend_from_slice(&encode_float_be(*f));
+        }
+        Value::Text(text) => {
+            out.push(tag::TEXT);
+            let collated = collated_bytes(text.as_str(), collation);
+            push_escaped_with_terminator(out, &collated);
+        }
+        Value::Blob(blob) => {
+            out.push(tag::BLOB);
+            push_escaped_with_terminator(out, blob);
+        }
+    }
+}
+
+/// Encodes `values` (one per ORDER BY term, in clause order) into a single order-preserving byte
+/// string: each column is tagged, collated (for text) and big-endian/sign-adjusted (for numbers)
+/// so the concatenation compares correctly with a plain `memcmp`; columns with
+/// `SortOrder::Desc` have every byte of their own segment inverted afterwards, so the *overall*
+/// comparison still simply ascends through the bytes left to right. This backs
+/// `Insn::MakeSortKey`.
+pub fn encode_sort_key(
+    values: &[Value],
+    order: &[SortOrder],
+    collations: &[Option<CollationSeq>],
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    for ((value, direction), collation) in values.iter().zip(order.iter()).zip(collations.iter()) {
+        let segment_start = out.len();
+        encode_column(&mut out, value, collation);
+        if *direction == SortOrder::Desc {
+            for byte in &mut out[segment_start..] {
+                *byte = !*byte;
+            }
+        }
+    }
+    out
+}
+
+/// One sorter row: an encoded key and its opaque record payload. Ordered solely by `key`, via
+/// `Vec<u8>`'s lexicographic `Ord` -- a plain `memcmp`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortEntry {
+    pub key: Vec<u8>,
+    pub record: Vec<u8>,
+}
+
+impl Ord for SortEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl PartialOrd for SortEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Bounded top-N sorter selected by `Insn::SorterOpen.limit`. Keeps only the `capacity` smallest
+/// encoded keys seen so far in a binary max-heap, so it never holds more than `capacity` rows in
+/// memory and turns sort cost from `O(n log n)` into `O(n log capacity)`.
+#[derive(Debug)]
+pub struct BoundedTopNSorter {
+    capacity: usize,
+    heap: BinaryHeap<SortEntry>,
+}
+
+impl BoundedTopNSorter {
+    /// Creates an empty sorter. A `capacity` of 0 is treated as 1.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            heap: BinaryHeap::with_capacity(capacity),
+        }
+    }
+
+    /// The key of the row that would be evicted first if a better one arrived, i.e. the sorter's
+    /// current worst retained row. `None` while the heap isn't yet full, since every row is
+    /// still retained unconditionally until then. Backs `Insn::SorterCompareWorst`.
+    pub fn worst_key(&self) -> Option<&[u8]> {
+        if self.heap.len() < self.capacity {
+            None
+        } else {
+            self.heap.peek().map(|entry| entry.key.as_slice())
+        }
+    }
+
+    /// Inserts a row, evicting the current worst retained row if the heap is already at capacity
+    /// and `key` compares better than it. Returns whether the row was actually retained --
+    /// `false` means the caller could have skipped `SorterInsert` for it entirely, which is what
+    /// `Insn::SorterCompareWorst` checks for ahead of time so expensive result columns don't even
+    /// need to be computed.
+    pub fn insert(&mut self, key: Vec<u8>, record: Vec<u8>) -> bool {
+        if self.heap.len() < self.capacity {
+            self.heap.push(SortEntry { key, record });
+            return true;
+        }
+        if self.heap.peek().is_some_and(|worst| key < worst.key) {
+            self.heap.pop();
+            self.heap.push(SortEntry { key, record });
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drains the heap in ascending key order (smallest first), the order `emit_order_by` reads
+    /// rows back in.
+    pub fn into_sorted_rows(self) -> Vec<SortEntry> {
+        let mut rows: Vec<SortEntry> = self.heap.into_vec();
+        rows.sort();
+        rows
+    }
+}
+
+/// A sorted run spilled to temporary storage by `ExternalMergeSorter`. On-disk format is a
+/// sequence of `[key_len: u32 LE][key][record_len: u32 LE][record]` rows, written in ascending
+/// key order so reading a run back sequentially is itself a sorted stream ready to merge.
+struct SpilledRun {
+    reader: BufReader<File>,
+    path: PathBuf,
+}
+
+impl SpilledRun {
+    fn read_row(&mut self) -> io::Result<Option<SortEntry>> {
+        let mut len_buf = [0u8; 4];
+        match self.reader.read_exact(&mut len_buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let key_len = u32::from_le_bytes(len_buf) as usize;
+        let mut key = vec![0u8; key_len];
+        self.reader.read_exact(&mut key)?;
+        self.reader.read_exact(&mut len_buf)?;
+        let record_len = u32::from_le_bytes(len_buf) as usize;
+        let mut record = vec![0u8; record_len];
+        self.reader.read_exact(&mut record)?;
+        Ok(Some(SortEntry { key, record }))
+    }
+}
+
+impl Drop for SpilledRun {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// One candidate in `MergedRunStream`'s merge heap: the current head row of a single run, plus
+/// which run it came from so the stream knows where to refill from after popping it. Ordered by
+/// `key` only.
+struct HeapEntry {
+    entry: SortEntry,
+    run_index: usize,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.entry.key == other.entry.key
+    }
+}
+impl Eq for HeapEntry {}
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.entry.key.cmp(&other.entry.key)
+    }
+}
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A k-way merge over already-sorted runs, emitted ascending as a single merged stream.
+/// Implemented as a binary min-heap (not a loser tree) over the current head of every run: the
+/// heap root is always the smallest head across all runs, so popping it and refilling from that
+/// same run's next row maintains the invariant in `O(log k)` per row instead of rescanning every
+/// run's head on each step.
+pub struct MergedRunStream {
+    runs: Vec<SpilledRun>,
+    heap: BinaryHeap<Reverse<HeapEntry>>,
+}
+
+impl MergedRunStream {
+    fn new(mut runs: Vec<SpilledRun>) -> io::Result<Self> {
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (run_index, run) in runs.iter_mut().enumerate() {
+            if let Some(entry) = run.read_row()? {
+                heap.push(Reverse(HeapEntry { entry, run_index }));
+            }
+        }
+        Ok(Self { runs, heap })
+    }
+
+    /// Pops and returns the next row in ascending key order across all runs, refilling the heap
+    /// from whichever run it came from. `None` once every run is exhausted. Backs
+    /// `Insn::SorterData` / `Insn::SorterNext` once the sorter has spilled at least one run.
+    pub fn next_row(&mut self) -> io::Result<Option<SortEntry>> {
+        let Reverse(top) = match self.heap.pop() {
+            Some(top) => top,
+            None => return Ok(None),
+        };
+        if let Some(next) = self.runs[top.run_index].read_row()? {
+            self.heap.push(Reverse(HeapEntry {
+                entry: next,
+                run_index: top.run_index,
+            }));
+        }
+        Ok(Some(top.entry))
+    }
+}
+
+/// The sorted stream `ExternalMergeSorter::into_sorted_stream` hands back: either the in-memory
+/// rows directly (nothing was ever spilled) or a `MergedRunStream` over the spilled runs.
+pub enum SortedStream {
+    InMemory(std::vec::IntoIter<SortEntry>),
+    Merged(MergedRunStream),
+}
+
+impl SortedStream {
+    pub fn next_row(&mut self) -> io::Result<Option<SortEntry>> {
+        match self {
+            SortedStream::InMemory(iter) => Ok(iter.next()),
+            SortedStream::Merged(merged) => merged.next_row(),
+        }
+    }
+}
+
+/// Unbounded ORDER BY sorter selected when `Insn::SorterOpen.limit` is `None`. Buffers rows in
+/// memory until `spill_threshold_bytes` is exceeded, at which point it sorts what it's holding
+/// and flushes it as a run to temporary storage, then resumes buffering. At drain time
+/// (`Insn::SorterSort`) the spilled runs (plus whatever's still buffered) are merged into one
+/// ascending stream by `MergedRunStream`. This is the standard external merge sort, and is what
+/// makes ORDER BY on inputs larger than the in-memory budget possible instead of OOMing.
+pub struct ExternalMergeSorter {
+    spill_threshold_bytes: usize,
+    in_memory_bytes: usize,
+    in_memory_rows: Vec<SortEntry>,
+    spilled_run_paths: Vec<PathBuf>,
+}
+
+impl ExternalMergeSorter {
+    pub fn new(spill_threshold_bytes: usize) -> Self {
+        Self {
+            spill_threshold_bytes,
+            in_memory_bytes: 0,
+            in_memory_rows: Vec::new(),
+            spilled_run_paths: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, record: Vec<u8>) -> io::Result<()> {
+        self.in_memory_bytes += key.len() + record.len();
+        self.in_memory_rows.push(SortEntry { key, record });
+        if self.in_memory_bytes > self.spill_threshold_bytes {
+            self.spill_current_run()?;
+        }
+        Ok(())
+    }
+
+    fn spill_current_run(&mut self) -> io::Result<()> {
+        if self.in_memory_rows.is_empty() {
+            return Ok(());
+        }
+        self.in_memory_rows.sort();
+        let path = std::env::temp_dir().join(format!(
+            "turso-sort-run-{}-{}.tmp",
+            std::process::id(),
+            self.spilled_run_paths.len()
+        ));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for entry in self.in_memory_rows.drain(..) {
+            writer.write_all(&(entry.key.len() as u32).to_le_bytes())?;
+            writer.write_all(&entry.key)?;
+            writer.write_all(&(entry.record.len() as u32).to_le_bytes())?;
+            writer.write_all(&entry.record)?;
+        }
+        writer.flush()?;
+        self.in_memory_bytes = 0;
+        self.spilled_run_paths.push(path);
+        Ok(())
+    }
+
+    /// Finalizes the sorter for draining. If nothing was ever spilled, sorts and returns the
+    /// in-memory rows directly -- no temporary storage is touched at all in the common case
+    /// where everything fit under `spill_threshold_bytes`. Otherwise flushes the remaining
+    /// in-memory rows as one last run and opens every spilled run for a k-way merge.
+    pub fn into_sorted_stream(mut self) -> io::Result<SortedStream> {
+        if self.spilled_run_paths.is_empty() {
+            self.in_memory_rows.sort();
+            return Ok(SortedStream::InMemory(self.in_memory_rows.into_iter()));
+        }
+        self.spill_current_run()?;
+        let mut runs = Vec::with_capacity(self.spilled_run_paths.len());
+        for path in &self.spilled_run_paths {
+            runs.push(SpilledRun {
+                reader: BufReader::new(File::open(path)?),
+                path: path.clone(),
+            });
+        }
+        Ok(SortedStream::Merged(MergedRunStream::new(runs)?))
+    }
+}